@@ -0,0 +1,793 @@
+use anyhow::{anyhow, Context, Result};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take};
+use nom::combinator::{map, value};
+use nom::multi::length_data;
+use nom::number::complete::*;
+use nom::sequence::{preceded, terminated, tuple};
+use nom::IResult;
+use std::convert::TryInto;
+use std::io::Read;
+use std::io::Write;
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub channel_count: u8,
+    pub skip: u16,
+    pub sample_rate: u32,
+    pub data_offset: u32,
+    /// Number of Opus elementary streams multiplexed into each frame. `1` for
+    /// mono/stereo (mapping family 0).
+    pub stream_count: u8,
+    /// Number of `stream_count` streams that are coupled (carry 2 channels).
+    pub coupled_count: u8,
+    /// Per-channel index into the decoded streams, present for mapping family 1
+    /// (`channel_count > 2`). Empty for mapping family 0.
+    pub mapping: Vec<u8>,
+    /// Sample offset where playback should resume on loop, or `0` if the track
+    /// doesn't loop.
+    pub loop_start: u32,
+    /// Sample offset where the loop region ends, or `0` if the track doesn't loop.
+    pub loop_end: u32,
+}
+
+pub fn header(input: &[u8]) -> IResult<&[u8], Header> {
+    let (input, (_, _, channel_count, _, sample_rate, data_offset, loop_start, loop_end, skip)) =
+        tuple((
+            tag(0x80000001u32.to_le_bytes()), // 0x00: magic
+            take(5usize),                     // 0x04: skip 5 bytes
+            le_u8,                            // 0x09: channel count
+            take(2usize),                     // 0x0a: skip 2 bytes
+            le_u32,                           // 0x0c: sample rate
+            le_u32,                           // 0x10: data offset
+            le_u32,                           // 0x14: loop start sample
+            le_u32,                           // 0x18: loop end sample
+            le_u16,                           // 0x1c: skip
+        ))(input)?;
+
+    // 0x1e: for surround NXOpus (mapping family 1), the multistream layout
+    // follows directly: stream count, coupled-stream count, then one mapping
+    // byte per channel.
+    let (input, (stream_count, coupled_count, mapping)) = if channel_count > 2 {
+        let (input, (stream_count, coupled_count, mapping)) =
+            tuple((le_u8, le_u8, take(channel_count as usize)))(input)?;
+        (input, (stream_count, coupled_count, mapping.to_vec()))
+    } else {
+        let coupled_count = if channel_count == 2 { 1 } else { 0 };
+        (input, (1, coupled_count, vec![]))
+    };
+
+    Ok((
+        input,
+        Header {
+            channel_count,
+            skip,
+            sample_rate,
+            data_offset,
+            stream_count,
+            coupled_count,
+            mapping,
+            loop_start,
+            loop_end,
+        },
+    ))
+}
+
+pub fn data_header(input: &[u8]) -> IResult<&[u8], u32> {
+    preceded(tag(0x80000004u32.to_le_bytes()), le_u32)(input)
+}
+
+pub fn packet(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    length_data(terminated(be_u32, take(4usize)))(input)
+}
+
+pub fn write_id_header(writer: &mut impl Write, header: &Header) -> Result<()> {
+    writer.write_all(b"OpusHead")?; // magic
+    writer.write_all(&[0x01])?; // version 1
+    writer.write_all(&[header.channel_count])?; // channels
+    writer.write_all(&header.skip.to_le_bytes())?; // pre-skip
+    writer.write_all(&header.sample_rate.to_le_bytes())?; // sample rate
+    writer.write_all(&[0x00, 0x00])?; // gain
+
+    if header.channel_count > 2 {
+        writer.write_all(&[0x01])?; // mapping family 1
+        writer.write_all(&[header.stream_count, header.coupled_count])?;
+        writer.write_all(&header.mapping)?;
+    } else {
+        writer.write_all(&[0x00])?; // mapping family 0
+    }
+
+    Ok(())
+}
+
+/// `OpusTags` packet carrying no user comments, for files with no loop points.
+pub const COMMENT_HEADER: &[u8] = b"OpusTags\x07\x00\x00\x00nx-opus\x00\x00\x00\x00";
+
+/// Vendor string used in every `OpusTags` packet this crate emits.
+pub const VENDOR_STRING: &[u8] = b"nx-opus";
+
+/// Builds an `OpusTags` packet, adding `LOOPSTART`/`LOOPLENGTH` user comments
+/// when `header` carries loop points (vgmstream's convention for seamless
+/// looping BGM), and emitting no comments at all otherwise.
+pub fn build_comment_header(header: &Header) -> Vec<u8> {
+    let mut comments = vec![];
+    if header.loop_start != 0 || header.loop_end != 0 {
+        comments.push(format!("LOOPSTART={}", header.loop_start));
+        comments.push(format!(
+            "LOOPLENGTH={}",
+            header.loop_end.saturating_sub(header.loop_start)
+        ));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"OpusTags");
+    out.extend_from_slice(&(VENDOR_STRING.len() as u32).to_le_bytes());
+    out.extend_from_slice(VENDOR_STRING);
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in &comments {
+        out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        out.extend_from_slice(comment.as_bytes());
+    }
+
+    out
+}
+
+/// Parses the `LOOPSTART`/`LOOPLENGTH` user comments out of a raw `OpusTags`
+/// packet, returning `(0, 0)` if neither is present.
+pub fn parse_loop_points(data: &[u8]) -> Result<(u32, u32)> {
+    if data.get(0..8) != Some(b"OpusTags".as_ref()) {
+        return Err(anyhow!("not an OpusTags packet"));
+    }
+
+    let vendor_len =
+        u32::from_le_bytes(data.get(8..12).context("truncated OpusTags packet")?.try_into()?)
+            as usize;
+    let mut pos = 12 + vendor_len;
+    let comment_count = u32::from_le_bytes(
+        data.get(pos..pos + 4)
+            .context("truncated OpusTags packet")?
+            .try_into()?,
+    );
+    pos += 4;
+
+    let mut loop_start = 0u32;
+    let mut loop_length = 0u32;
+
+    for _ in 0..comment_count {
+        let len = u32::from_le_bytes(
+            data.get(pos..pos + 4)
+                .context("truncated OpusTags packet")?
+                .try_into()?,
+        ) as usize;
+        pos += 4;
+        let comment = data
+            .get(pos..pos + len)
+            .context("truncated OpusTags packet")?;
+        pos += len;
+
+        if let Some(value) = comment.strip_prefix(b"LOOPSTART=") {
+            loop_start = std::str::from_utf8(value)?.parse().unwrap_or(0);
+        } else if let Some(value) = comment.strip_prefix(b"LOOPLENGTH=") {
+            loop_length = std::str::from_utf8(value)?.parse().unwrap_or(0);
+        }
+    }
+
+    Ok((loop_start, loop_start.saturating_add(loop_length)))
+}
+
+/// Size in bytes of a mapping-family-0 NXOpus `Header` block (mono/stereo).
+pub const HEADER_SIZE: u32 = 0x20;
+
+/// Size in bytes of the NXOpus `Header` block that [`write_header`] will emit,
+/// including the mapping-family-1 multistream table for surround audio.
+pub fn header_size(header: &Header) -> u32 {
+    if header.channel_count > 2 {
+        0x1e + 2 + header.channel_count as u32
+    } else {
+        HEADER_SIZE
+    }
+}
+
+/// Writes the `0x80000001` NXOpus header block, mirroring the layout parsed by [`header`].
+pub fn write_header(writer: &mut impl Write, header: &Header) -> Result<()> {
+    writer.write_all(&0x80000001u32.to_le_bytes())?; // magic
+    writer.write_all(&[0x00; 5])?; // 0x04: unknown
+    writer.write_all(&[header.channel_count])?; // 0x09: channel count
+    writer.write_all(&[0x00; 2])?; // 0x0a: unknown
+    writer.write_all(&header.sample_rate.to_le_bytes())?; // 0x0c: sample rate
+    writer.write_all(&header.data_offset.to_le_bytes())?; // 0x10: data offset
+    writer.write_all(&header.loop_start.to_le_bytes())?; // 0x14: loop start sample
+    writer.write_all(&header.loop_end.to_le_bytes())?; // 0x18: loop end sample
+    writer.write_all(&header.skip.to_le_bytes())?; // 0x1c: skip
+
+    if header.channel_count > 2 {
+        // 0x1e: multistream layout, mirroring the mapping-family-1 table in `header`
+        writer.write_all(&[header.stream_count, header.coupled_count])?;
+        writer.write_all(&header.mapping)?;
+    } else {
+        // 0x1e..0x20: pad out to `HEADER_SIZE` so `header.data_offset` points directly
+        // at the following `0x80000004` data section.
+        writer.write_all(&[0x00; 2])?;
+    }
+
+    Ok(())
+}
+
+/// Writes the `0x80000004` NXOpus data section header, mirroring [`data_header`].
+pub fn write_data_header(writer: &mut impl Write, length: u32) -> Result<()> {
+    writer.write_all(&0x80000004u32.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Writes a single NXOpus packet: a big-endian length prefix, the trailing 4-byte
+/// field skipped by [`packet`] via `take(4)`, then the raw Opus packet bytes.
+pub fn write_packet(writer: &mut impl Write, packet: &[u8]) -> Result<()> {
+    writer.write_all(&(packet.len() as u32).to_be_bytes())?;
+    writer.write_all(&[0x00; 4])?;
+    writer.write_all(packet)?;
+
+    Ok(())
+}
+
+/// Parses the channel count, pre-skip, sample rate, and (for mapping family 1)
+/// multistream layout out of a raw `OpusHead` Ogg packet. `data_offset` is left
+/// at `0` for the caller to fill in.
+pub fn parse_id_header(data: &[u8]) -> Result<Header> {
+    if data.get(0..8) != Some(b"OpusHead".as_ref()) {
+        return Err(anyhow!("not an OpusHead packet"));
+    }
+
+    let channel_count = *data.get(9).context("truncated OpusHead packet")?;
+    let skip = u16::from_le_bytes(data.get(10..12).context("truncated OpusHead packet")?.try_into()?);
+    let sample_rate =
+        u32::from_le_bytes(data.get(12..16).context("truncated OpusHead packet")?.try_into()?);
+    let mapping_family = *data.get(18).context("truncated OpusHead packet")?;
+
+    let (stream_count, coupled_count, mapping) = if mapping_family == 1 {
+        let stream_count = *data.get(19).context("truncated OpusHead packet")?;
+        let coupled_count = *data.get(20).context("truncated OpusHead packet")?;
+        let mapping = data
+            .get(21..21 + channel_count as usize)
+            .context("truncated OpusHead packet")?
+            .to_vec();
+        (stream_count, coupled_count, mapping)
+    } else {
+        let coupled_count = if channel_count == 2 { 1 } else { 0 };
+        (1, coupled_count, vec![])
+    };
+
+    Ok(Header {
+        channel_count,
+        skip,
+        sample_rate,
+        data_offset: 0,
+        stream_count,
+        coupled_count,
+        mapping,
+        loop_start: 0,
+        loop_end: 0,
+    })
+}
+
+/// The per-channel stream mapping, defaulting to the implicit identity mapping
+/// used by mapping family 0 (`header.mapping` is only populated for family 1).
+pub fn effective_mapping(header: &Header) -> Vec<u8> {
+    if header.mapping.is_empty() {
+        (0..header.channel_count).collect()
+    } else {
+        header.mapping.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct OpusPacket {
+    pub config: u8,
+    pub stereo: bool,
+    pub frames: u8,
+}
+
+pub fn opus_packet(input: &[u8]) -> IResult<&[u8], OpusPacket> {
+    use nom::bits::{bits, complete::*};
+
+    bits(map::<_, _, _, nom::error::Error<_>, _, _>(
+        tuple((
+            take(5usize),
+            map(take(1usize), |x: u8| x != 0),
+            alt((
+                value(1, tag(0usize, 2usize)),
+                value(2, tag(1usize, 2usize)),
+                value(2, tag(2usize, 2usize)),
+                preceded(
+                    tag(3usize, 2usize),
+                    preceded(take::<_, u8, _, _>(2usize), take(6usize)),
+                ),
+            )),
+        )),
+        |(config, stereo, frames)| OpusPacket {
+            config,
+            stereo,
+            frames,
+        },
+    ))(input)
+}
+
+/// Writes one frame-length value using the 1-or-2-byte scheme from RFC 6716
+/// section 3.1 ("frame length coding"): values below 252 fit in a single byte,
+/// larger ones spill into a second.
+fn write_self_delimiting_length(out: &mut Vec<u8>, len: usize) {
+    if len < 252 {
+        out.push(len as u8);
+    } else {
+        let rest = len - 252;
+        out.push(252 + (rest % 4) as u8);
+        out.push((rest / 4) as u8);
+    }
+}
+
+/// Reads one RFC 6716 section 3.1 frame-length value, returning the decoded
+/// length and the remaining data after it.
+fn read_self_delimiting_length(data: &[u8]) -> Result<(usize, &[u8])> {
+    let b0 = *data.first().context("truncated Opus packet")?;
+
+    if b0 < 252 {
+        Ok((b0 as usize, &data[1..]))
+    } else {
+        let b1 = *data.get(1).context("truncated Opus packet")?;
+        let len = 252 + (b0 - 252) as usize + b1 as usize * 4;
+        Ok((len, &data[2..]))
+    }
+}
+
+/// Converts a normal Opus packet into the RFC 6716 appendix B self-delimiting
+/// form by adding an explicit length for the packet's last frame (normally left
+/// implicit, inferred from the enclosing framing's total packet size), so the
+/// packet's end can be found without that framing. Returns the packet unchanged
+/// in length-prefix position but with the extra length field spliced in at the
+/// point appendix B specifies for the packet's frame-count code.
+fn self_delimit_packet(packet: &[u8]) -> Result<Vec<u8>> {
+    let toc = *packet.first().context("empty Opus packet")?;
+    let code = toc & 0x3;
+    let rest = &packet[1..];
+
+    let mut out = vec![toc];
+
+    match code {
+        0 => {
+            // Single frame spanning the rest of the packet.
+            write_self_delimiting_length(&mut out, rest.len());
+            out.extend_from_slice(rest);
+        }
+        1 => {
+            // Two frames of equal, otherwise implicit, size.
+            if rest.len() % 2 != 0 {
+                return Err(anyhow!("code 1 packet has odd-length frame data"));
+            }
+            write_self_delimiting_length(&mut out, rest.len() / 2);
+            out.extend_from_slice(rest);
+        }
+        2 => {
+            // First frame's size is already coded; splice in the second's.
+            let (len1, after_len1) = read_self_delimiting_length(rest)?;
+            if len1 > after_len1.len() {
+                return Err(anyhow!("truncated Opus packet"));
+            }
+            let (frame1, frame2) = after_len1.split_at(len1);
+            write_self_delimiting_length(&mut out, len1);
+            write_self_delimiting_length(&mut out, frame2.len());
+            out.extend_from_slice(frame1);
+            out.extend_from_slice(frame2);
+        }
+        _ => {
+            let frame_count_byte = *rest.first().context("truncated Opus packet")?;
+            let vbr = frame_count_byte & 0x80 != 0;
+            let has_padding = frame_count_byte & 0x40 != 0;
+            let frame_count = (frame_count_byte & 0x3f) as usize;
+
+            let mut cur = &rest[1..];
+            let mut padding_header = vec![];
+            let mut padding_len = 0usize;
+            if has_padding {
+                loop {
+                    let b = *cur.first().context("truncated Opus packet")?;
+                    padding_header.push(b);
+                    cur = &cur[1..];
+                    if b == 255 {
+                        padding_len += 254;
+                    } else {
+                        padding_len += b as usize;
+                        break;
+                    }
+                }
+            }
+
+            let mut frame_lengths = Vec::with_capacity(frame_count);
+            if vbr {
+                for _ in 0..frame_count.saturating_sub(1) {
+                    let (len, after) = read_self_delimiting_length(cur)?;
+                    frame_lengths.push(len);
+                    cur = after;
+                }
+            }
+
+            let known: usize = frame_lengths.iter().sum();
+            let data_and_padding = cur;
+            if padding_len > data_and_padding.len() || known > data_and_padding.len() - padding_len
+            {
+                return Err(anyhow!("truncated Opus packet"));
+            }
+            let data = &data_and_padding[..data_and_padding.len() - padding_len];
+            let padding = &data_and_padding[data.len()..];
+
+            let last_len = if vbr || frame_count == 0 {
+                data.len() - known
+            } else {
+                if data.len() % frame_count != 0 {
+                    return Err(anyhow!("CBR frame data not evenly divisible"));
+                }
+                data.len() / frame_count
+            };
+
+            out.push(frame_count_byte);
+            out.extend_from_slice(&padding_header);
+            for len in &frame_lengths {
+                write_self_delimiting_length(&mut out, *len);
+            }
+            write_self_delimiting_length(&mut out, last_len);
+            out.extend_from_slice(data);
+            out.extend_from_slice(padding);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Reads one self-delimited packet (as produced by [`self_delimit_packet`])
+/// from the front of `data`, returning the equivalent normal-framed packet
+/// (with the extra self-delimiting length field removed again) and the number
+/// of bytes it occupied in `data`.
+fn split_self_delimited_packet(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let toc = *data.first().context("truncated multistream packet")?;
+    let code = toc & 0x3;
+    let rest = &data[1..];
+    let mut pos = 1;
+
+    let mut packet = vec![toc];
+
+    match code {
+        0 => {
+            let (len, after) = read_self_delimiting_length(rest)?;
+            pos += rest.len() - after.len();
+            let frame = after.get(..len).context("truncated multistream packet")?;
+            pos += len;
+            packet.extend_from_slice(frame);
+        }
+        1 => {
+            let (len, after) = read_self_delimiting_length(rest)?;
+            pos += rest.len() - after.len();
+            let frames = after
+                .get(..len * 2)
+                .context("truncated multistream packet")?;
+            pos += len * 2;
+            packet.extend_from_slice(frames);
+        }
+        2 => {
+            let (len1, after1) = read_self_delimiting_length(rest)?;
+            let (len2, after2) = read_self_delimiting_length(after1)?;
+            pos += rest.len() - after2.len();
+            let frames = after2
+                .get(..len1 + len2)
+                .context("truncated multistream packet")?;
+            pos += len1 + len2;
+            // Normal framing keeps only the first frame's explicit length.
+            write_self_delimiting_length(&mut packet, len1);
+            packet.extend_from_slice(frames);
+        }
+        _ => {
+            let frame_count_byte = *rest.first().context("truncated multistream packet")?;
+            let vbr = frame_count_byte & 0x80 != 0;
+            let has_padding = frame_count_byte & 0x40 != 0;
+            let frame_count = (frame_count_byte & 0x3f) as usize;
+
+            let mut cur = &rest[1..];
+            pos += 1;
+            let mut padding_header = vec![];
+            let mut padding_len = 0usize;
+            if has_padding {
+                loop {
+                    let b = *cur.first().context("truncated multistream packet")?;
+                    padding_header.push(b);
+                    cur = &cur[1..];
+                    pos += 1;
+                    if b == 255 {
+                        padding_len += 254;
+                    } else {
+                        padding_len += b as usize;
+                        break;
+                    }
+                }
+            }
+
+            let mut frame_lengths = Vec::with_capacity(frame_count);
+            if vbr {
+                for _ in 0..frame_count.saturating_sub(1) {
+                    let (len, after) = read_self_delimiting_length(cur)?;
+                    pos += cur.len() - after.len();
+                    frame_lengths.push(len);
+                    cur = after;
+                }
+            }
+
+            // Self-delimiting always adds an explicit length for the last
+            // frame; normal framing leaves it implicit, so it's read here to
+            // find the packet boundary but not written back out.
+            let (last_len, after_last) = read_self_delimiting_length(cur)?;
+            pos += cur.len() - after_last.len();
+            cur = after_last;
+
+            let known: usize = frame_lengths.iter().sum();
+            let data_len = if vbr || frame_count == 0 {
+                known + last_len
+            } else {
+                last_len * frame_count
+            };
+            let data = cur
+                .get(..data_len)
+                .context("truncated multistream packet")?;
+            let padding = cur
+                .get(data_len..data_len + padding_len)
+                .context("truncated multistream packet")?;
+            pos += data_len + padding_len;
+
+            packet.push(frame_count_byte);
+            packet.extend_from_slice(&padding_header);
+            for len in &frame_lengths {
+                write_self_delimiting_length(&mut packet, *len);
+            }
+            packet.extend_from_slice(data);
+            packet.extend_from_slice(padding);
+        }
+    }
+
+    Ok((packet, pos))
+}
+
+/// Concatenates one frame's worth of per-stream Opus packets into the single
+/// logical packet a multistream Opus decoder expects: every packet but the
+/// last is converted to the RFC 6716 appendix B self-delimiting form (which
+/// makes its end self-describing), and the last is left in its normal form.
+pub fn concat_multistream_packet(streams: &[&[u8]]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+
+    for (i, stream) in streams.iter().enumerate() {
+        if i + 1 < streams.len() {
+            out.extend_from_slice(&self_delimit_packet(stream)?);
+        } else {
+            out.extend_from_slice(stream);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Splits a concatenated multistream Opus packet back into its per-stream
+/// packets, the inverse of [`concat_multistream_packet`].
+pub fn split_multistream_packet(data: &[u8], stream_count: usize) -> Result<Vec<Vec<u8>>> {
+    let mut rest = data;
+    let mut streams = Vec::with_capacity(stream_count);
+
+    for i in 0..stream_count {
+        if i + 1 < stream_count {
+            let (stream, consumed) = split_self_delimited_packet(rest)?;
+            if consumed > rest.len() {
+                return Err(anyhow!("truncated multistream packet"));
+            }
+            streams.push(stream);
+            rest = &rest[consumed..];
+        } else {
+            streams.push(rest.to_vec());
+        }
+    }
+
+    Ok(streams)
+}
+
+pub fn frame_size(config: u8) -> u64 {
+    const SILK: &[u64] = &[100, 200, 400, 600];
+    const HYBRID: &[u64] = &[100, 200];
+    const CELT: &[u64] = &[25, 50, 100, 200];
+
+    let sizes = match config {
+        0..=11 => SILK,
+        12..=15 => HYBRID,
+        16..=31 => CELT,
+        _ => unreachable!(),
+    };
+
+    let idx = config as usize % sizes.len();
+
+    sizes[idx]
+}
+
+/// Writes a minimal canonical 44-byte PCM WAV header for `data_len` bytes of
+/// interleaved 16-bit samples.
+pub fn write_wav_header(
+    writer: &mut impl Write,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32,
+) -> Result<()> {
+    let block_align = channels * 2;
+    let byte_rate = sample_rate * block_align as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Writes the 12-byte RTP header defined by RFC 3550 (as profiled for Opus by
+/// RFC 7587): version 2, no padding/extension/CSRCs, a dynamic payload type,
+/// sequence number, timestamp, and SSRC.
+pub fn write_rtp_header(
+    writer: &mut impl Write,
+    payload_type: u8,
+    marker: bool,
+    sequence_number: u16,
+    timestamp: u32,
+    ssrc: u32,
+) -> Result<()> {
+    writer.write_all(&[0x80])?; // V=2, P=0, X=0, CC=0
+    writer.write_all(&[(marker as u8) << 7 | (payload_type & 0x7f)])?;
+    writer.write_all(&sequence_number.to_be_bytes())?;
+    writer.write_all(&timestamp.to_be_bytes())?;
+    writer.write_all(&ssrc.to_be_bytes())?;
+
+    Ok(())
+}
+
+/// Size of the internal buffer [`NxOpusReader`] refills from the underlying
+/// reader at a time.
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+/// Incrementally parses a NXOpus stream from an `impl Read`, without loading
+/// the whole file into memory. Parses the `Header` and data section header up
+/// front, then hands out audio packets one at a time via `Iterator`, refilling
+/// a 64 KiB buffer as needed and carrying partial data across reads.
+/// The buffer is grown only when a single packet does not fit in it.
+///
+/// `header`, `data_header`, and `packet` remain the parsing primitives; this
+/// just drives them over a growable buffer instead of a single in-memory slice.
+pub struct NxOpusReader<R> {
+    reader: R,
+    pub header: Header,
+    pub data_length: u32,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    eof: bool,
+}
+
+impl<R: Read> NxOpusReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        // A `Header` is at most `HEADER_SIZE` bytes, plus up to 2 + 255
+        // multistream mapping bytes for surround audio; read enough up front
+        // that `header`/`data_header` always see a complete prefix.
+        let mut head_buf = vec![0u8; HEADER_SIZE as usize + 2 + 255];
+        let mut head_len = 0;
+        while head_len < head_buf.len() {
+            let n = reader.read(&mut head_buf[head_len..])?;
+            if n == 0 {
+                break;
+            }
+            head_len += n;
+        }
+        head_buf.truncate(head_len);
+
+        let (rest, header) = header(&head_buf).map_err(|x| anyhow!("{}", x))?;
+
+        // `header` only consumes the fixed fields (and, for surround audio, the
+        // multistream table); the `0x80000004` data section actually starts at
+        // `header.data_offset`, so skip forward to it before parsing further.
+        let consumed = head_len - rest.len();
+        let gap = (header.data_offset as usize)
+            .checked_sub(consumed)
+            .context("data_offset before end of header")?;
+        // `data_offset` can point past the prefix read above; read on until the
+        // 8-byte data section header is buffered too.
+        let mut tail = rest.to_vec();
+        if tail.len() < gap + 8 {
+            let missing = (gap + 8 - tail.len()) as u64;
+            (&mut reader).take(missing).read_to_end(&mut tail)?;
+        }
+        let rest = tail.get(gap..).context("truncated header")?;
+
+        let (rest, data_length) = data_header(rest).map_err(|x| anyhow!("{}", x))?;
+
+        let mut buf = vec![0u8; READ_BUF_SIZE];
+        buf[..rest.len()].copy_from_slice(rest);
+
+        Ok(NxOpusReader {
+            reader,
+            header,
+            data_length,
+            buf,
+            pos: 0,
+            filled: rest.len(),
+            eof: false,
+        })
+    }
+
+    /// Compacts unparsed bytes to the front of the buffer, growing it if it's
+    /// already full, then reads more data from the underlying reader.
+    fn fill(&mut self) -> Result<()> {
+        if self.pos > 0 {
+            self.buf.copy_within(self.pos..self.filled, 0);
+            self.filled -= self.pos;
+            self.pos = 0;
+        }
+
+        if self.filled == self.buf.len() {
+            self.buf.resize(self.buf.len() * 2, 0);
+        }
+
+        let n = self.reader.read(&mut self.buf[self.filled..])?;
+        self.filled += n;
+        if n == 0 {
+            self.eof = true;
+        }
+
+        Ok(())
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let available = self.filled - self.pos;
+
+            // length prefix (4) + trailing field (4)
+            if available >= 8 {
+                let len_bytes: [u8; 4] = self.buf[self.pos..self.pos + 4].try_into().unwrap();
+                let needed = 8 + u32::from_be_bytes(len_bytes) as usize;
+
+                if available >= needed {
+                    let slice = &self.buf[self.pos..self.pos + needed];
+                    let data = packet(slice).map_err(|x| anyhow!("{}", x))?.1.to_vec();
+                    self.pos += needed;
+                    return Ok(Some(data));
+                }
+            }
+
+            if self.eof {
+                return if available == 0 {
+                    Ok(None)
+                } else {
+                    Err(anyhow!("truncated packet at end of stream"))
+                };
+            }
+
+            self.fill()?;
+        }
+    }
+}
+
+impl<R: Read> Iterator for NxOpusReader<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
@@ -1,160 +1,325 @@
 use anyhow::{anyhow, Context, Result};
-use nom::branch::alt;
-use nom::bytes::complete::{tag, take};
-use nom::combinator::{iterator, map, value};
-use nom::multi::length_data;
-use nom::number::complete::*;
-use nom::sequence::{preceded, terminated, tuple};
-use nom::IResult;
+use nx_opus::*;
+use ogg::reading::PacketReader;
 use ogg::writing::*;
+use opus::{Channels, Decoder as OpusDecoder};
 use std::env::args_os;
-use std::fs;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::stdout;
 
-#[derive(Debug)]
-pub struct Header {
-    pub channel_count: u8,
-    pub skip: u16,
-    pub sample_rate: u32,
-    pub data_offset: u32,
-}
+/// Decodes a Switch NXOpus file to PCM via libopus and writes it out as a WAV file.
+fn to_wav(in_path: &OsStr, out_path: &OsStr) -> Result<()> {
+    let in_file = File::open(in_path)?;
+    let mut out_file = File::create(out_path)?;
 
-pub fn header(input: &[u8]) -> IResult<&[u8], Header> {
-    map(
-        tuple((
-            tag(0x80000001u32.to_le_bytes()), // 0x00: magic
-            take(5usize),                     // 0x04: skip 5 bytes
-            le_u8,                            // 0x09: channel count
-            take(2usize),                     // 0x0a: skip 2 bytes
-            le_u32,                           // 0x0c: sample rate
-            le_u32,                           // 0x10: data offset
-            take(8usize),                     // 0x14: skip 8 bytes
-            le_u16,                           // 0x1c: skip
-        )),
-        |(_, _, channel_count, _, sample_rate, data_offset, _, skip)| Header {
-            channel_count,
-            skip,
-            sample_rate,
-            data_offset,
-        },
-    )(input)
-}
+    let mut reader = NxOpusReader::new(in_file)?;
 
-pub fn data_header(input: &[u8]) -> IResult<&[u8], u32> {
-    preceded(tag(0x80000004u32.to_le_bytes()), le_u32)(input)
-}
+    let stream_count = reader.header.stream_count as usize;
+    let coupled_count = reader.header.coupled_count as usize;
+    let mapping = effective_mapping(&reader.header);
+    let channel_count = reader.header.channel_count as usize;
+    let skip = reader.header.skip as usize;
+    let decoded_channel_count = 2 * coupled_count + (stream_count - coupled_count);
 
-pub fn packet(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    length_data(terminated(be_u32, take(4usize)))(input)
-}
+    let mut decoders: Vec<OpusDecoder> = (0..stream_count)
+        .map(|s| {
+            let channels = if s < coupled_count {
+                Channels::Stereo
+            } else {
+                Channels::Mono
+            };
+            OpusDecoder::new(48000, channels).map_err(|x| anyhow!("{}", x))
+        })
+        .collect::<Result<_>>()?;
 
-pub fn write_id_header(writer: &mut impl Write, header: &Header) -> Result<()> {
-    writer.write_all(b"OpusHead")?; // magic
-    writer.write_all(&[0x01])?; // version 1
-    writer.write_all(&[header.channel_count])?; // channels
-    writer.write_all(&header.skip.to_le_bytes())?; // pre-skip
-    writer.write_all(&header.sample_rate.to_le_bytes())?; // sample rate
-    writer.write_all(&[0x00, 0x00])?; // gain
-    writer.write_all(&[0x00])?; // mapping family 0
+    let mut pcm: Vec<i16> = vec![];
 
-    Ok(())
-}
+    loop {
+        let mut frame: Vec<Vec<u8>> = Vec::with_capacity(stream_count);
+        for _ in 0..stream_count {
+            match reader.next() {
+                Some(packet) => frame.push(packet?),
+                None => break,
+            }
+        }
+        if frame.is_empty() {
+            break;
+        }
 
-pub const COMMENT_HEADER: &[u8] = b"OpusTags\x07\x00\x00\x00nx-opus\x00\x00\x00\x00";
+        let opus = opus_packet(&frame[0]).map_err(|x| anyhow!("{}", x))?.1;
+        // A packet can bundle more than one frame (`opus.frames`); the decoded
+        // buffer has to fit all of them, not just one.
+        let samples = (48000 * frame_size(opus.config) / 10000) as usize * opus.frames as usize;
 
-#[derive(Debug)]
-pub struct OpusPacket {
-    pub config: u8,
-    pub stereo: bool,
-    pub frames: u8,
-}
+        let mut decoded_channels = vec![vec![0i16; samples]; decoded_channel_count];
 
-pub fn opus_packet(input: &[u8]) -> IResult<&[u8], OpusPacket> {
-    use nom::bits::{bits, complete::*};
-
-    bits(map::<_, _, _, nom::error::Error<_>, _, _>(
-        tuple((
-            take(5usize),
-            map(take(1usize), |x: u8| x != 0),
-            alt((
-                value(1, tag(0usize, 2usize)),
-                value(2, tag(1usize, 2usize)),
-                value(2, tag(2usize, 2usize)),
-                preceded(
-                    tag(3usize, 2usize),
-                    preceded(take::<_, u8, _, _>(2usize), take(6usize)),
-                ),
-            )),
-        )),
-        |(config, stereo, frames)| OpusPacket {
-            config,
-            stereo,
-            frames,
-        },
-    ))(input)
-}
+        for (s, sub_packet) in frame.iter().enumerate() {
+            if s < coupled_count {
+                let mut interleaved = vec![0i16; samples * 2];
+                decoders[s]
+                    .decode(sub_packet, &mut interleaved, false)
+                    .map_err(|x| anyhow!("{}", x))?;
+                for i in 0..samples {
+                    decoded_channels[2 * s][i] = interleaved[i * 2];
+                    decoded_channels[2 * s + 1][i] = interleaved[i * 2 + 1];
+                }
+            } else {
+                let idx = 2 * coupled_count + (s - coupled_count);
+                decoders[s]
+                    .decode(sub_packet, &mut decoded_channels[idx], false)
+                    .map_err(|x| anyhow!("{}", x))?;
+            }
+        }
 
-pub fn frame_size(config: u8) -> u64 {
-    const SILK: &[u64] = &[100, 200, 400, 600];
-    const HYBRID: &[u64] = &[100, 200];
-    const CELT: &[u64] = &[25, 50, 100, 200];
+        for i in 0..samples {
+            for &m in &mapping {
+                let sample = if m == 0xff {
+                    0
+                } else {
+                    decoded_channels[m as usize][i]
+                };
+                pcm.push(sample);
+            }
+        }
+    }
 
-    let sizes = match config {
-        0..=11 => SILK,
-        12..=15 => HYBRID,
-        16..=31 => CELT,
-        _ => unreachable!(),
-    };
+    // honor pre-skip by discarding the leading `skip` sample-frames
+    let skip_values = skip.min(pcm.len() / channel_count) * channel_count;
 
-    let idx = config as usize % sizes.len();
+    write_wav_header(
+        &mut out_file,
+        channel_count as u16,
+        48000,
+        ((pcm.len() - skip_values) * 2) as u32,
+    )?;
+    for sample in &pcm[skip_values..] {
+        out_file.write_all(&sample.to_le_bytes())?;
+    }
 
-    sizes[idx]
+    Ok(())
 }
 
-fn main() -> Result<()> {
-    let file = fs::read(args_os().nth(1).context("Missing path!")?)?;
-    let out_file = File::create(args_os().nth(2).context("Missing path!")?)?;
+/// Converts a Switch NXOpus file into an Ogg Opus file.
+fn to_ogg(in_path: &OsStr, out_path: &OsStr) -> Result<()> {
+    let in_file = File::open(in_path)?;
+    let out_file = File::create(out_path)?;
     let mut writer = PacketWriter::new(out_file);
 
-    let header = header(&file).map_err(|x| anyhow!("{}", x))?.1;
-    dbg!(&header);
+    let mut reader = NxOpusReader::new(in_file)?;
+
+    let channel_count = reader.header.channel_count;
+    let stream_count = reader.header.stream_count as usize;
 
     let mut id_header: Vec<u8> = vec![];
-    write_id_header(&mut id_header, &header)?;
+    write_id_header(&mut id_header, &reader.header)?;
     writer.write_packet(id_header.into(), 0, PacketWriteEndInfo::EndPage, 0)?;
 
-    writer.write_packet(COMMENT_HEADER.into(), 0, PacketWriteEndInfo::EndPage, 0)?;
+    let comment_header = build_comment_header(&reader.header);
+    writer.write_packet(comment_header.into(), 0, PacketWriteEndInfo::EndPage, 0)?;
+
+    let mut peekable = reader.peekable();
 
-    let (data, length) =
-        data_header(&file[header.data_offset as usize..]).map_err(|x| anyhow!("{}", x))?;
+    if channel_count > 2 {
+        // Surround NXOpus stores one packet per elementary stream per frame; a
+        // multistream Opus packet is those streams' packets concatenated, so
+        // group and splice them back together instead of writing one Ogg
+        // packet per stream.
+        let mut pos = 0;
 
-    dbg!(length);
+        loop {
+            let mut frame: Vec<Vec<u8>> = Vec::with_capacity(stream_count);
+            for _ in 0..stream_count {
+                match peekable.next() {
+                    Some(packet) => frame.push(packet?),
+                    None => break,
+                }
+            }
+            if frame.is_empty() {
+                break;
+            }
 
-    let mut iter = iterator(data, packet);
+            let refs: Vec<&[u8]> = frame.iter().map(|p| p.as_slice()).collect();
+            let opus = opus_packet(refs[0]).map_err(|x| anyhow!("{}", x))?.1;
+            let size = frame_size(opus.config);
+            let duration = 48000 * size / 10000;
 
-    let mut peekable = iter.into_iter().enumerate().peekable();
+            pos += duration;
 
-    let mut pos = 0;
+            let end = if peekable.peek().is_none() {
+                PacketWriteEndInfo::EndStream
+            } else {
+                PacketWriteEndInfo::EndPage
+            };
+            let logical_packet = concat_multistream_packet(&refs)?;
+            writer.write_packet(logical_packet.into(), 0, end, pos)?;
+        }
+    } else {
+        let mut pos = 0;
+        let mut i = 0;
 
-    while let Some((i, packet)) = peekable.next() {
-        let opus = opus_packet(packet).map_err(|x| anyhow!("{}", x))?.1;
-        let size = frame_size(opus.config);
-        let duration = 48000 * size / 10000;
+        while let Some(packet) = peekable.next() {
+            let packet = packet?;
+            let opus = opus_packet(&packet).map_err(|x| anyhow!("{}", x))?.1;
+            let size = frame_size(opus.config);
+            let duration = 48000 * size / 10000;
+
+            pos += duration;
+            i += 1;
+
+            let end = if peekable.peek().is_none() {
+                PacketWriteEndInfo::EndStream
+            } else if i % (channel_count as usize) == 0 {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            writer.write_packet(packet.into(), 0, end, pos)?;
+        }
+    }
 
-        pos += duration;
+    Ok(())
+}
+
+/// Packetizes a Switch NXOpus file as an RFC 7587 RTP Opus stream and writes it,
+/// length-prefixed, to `out_path` (or stdout, if `out_path` is `-`).
+fn to_rtp(in_path: &OsStr, out_path: &OsStr, payload_type: u8) -> Result<()> {
+    let in_file = File::open(in_path)?;
+    let mut reader = NxOpusReader::new(in_file)?;
+
+    let channel_count = reader.header.channel_count;
+    let stream_count = reader.header.stream_count as usize;
+
+    let mut out: Box<dyn Write> = if out_path.to_str() == Some("-") {
+        Box::new(stdout())
+    } else {
+        Box::new(File::create(out_path)?)
+    };
 
-        let end = if peekable.peek().is_none() {
-            PacketWriteEndInfo::EndStream
-        } else if (i + 1) % (header.channel_count as usize) == 0 {
-            PacketWriteEndInfo::EndPage
+    let ssrc: u32 = rand::random();
+    let mut sequence_number: u16 = 0;
+    let mut timestamp: u32 = 0;
+    let mut marker = true;
+
+    loop {
+        let mut frame: Vec<Vec<u8>> = Vec::with_capacity(stream_count);
+        for _ in 0..stream_count {
+            match reader.next() {
+                Some(packet) => frame.push(packet?),
+                None => break,
+            }
+        }
+        if frame.is_empty() {
+            break;
+        }
+
+        let payload = if channel_count > 2 {
+            let refs: Vec<&[u8]> = frame.iter().map(|p| p.as_slice()).collect();
+            concat_multistream_packet(&refs)?
         } else {
-            PacketWriteEndInfo::NormalPacket
+            frame.into_iter().next().unwrap()
         };
-        writer.write_packet(packet.into(), 0, end, pos)?;
+
+        let opus = opus_packet(&payload).map_err(|x| anyhow!("{}", x))?.1;
+        let duration = (48000 * frame_size(opus.config) / 10000) as u32;
+
+        let mut rtp_packet = vec![];
+        write_rtp_header(
+            &mut rtp_packet,
+            payload_type,
+            marker,
+            sequence_number,
+            timestamp,
+            ssrc,
+        )?;
+        rtp_packet.extend_from_slice(&payload);
+
+        out.write_all(&(rtp_packet.len() as u32).to_be_bytes())?;
+        out.write_all(&rtp_packet)?;
+
+        sequence_number = sequence_number.wrapping_add(1);
+        timestamp = timestamp.wrapping_add(duration);
+        marker = false;
     }
 
-    iter.finish().map_err(|x| anyhow!("{}", x))?;
+    Ok(())
+}
+
+/// Converts an Ogg Opus file back into a Switch NXOpus file, the inverse of [`to_ogg`].
+fn to_nxopus(in_path: &OsStr, out_path: &OsStr) -> Result<()> {
+    let in_file = File::open(in_path)?;
+    let mut reader = PacketReader::new(in_file);
+    let mut out_file = File::create(out_path)?;
+
+    let id_packet = reader
+        .read_packet()
+        .map_err(|x| anyhow!("{}", x))?
+        .context("missing OpusHead packet")?;
+    let mut header = parse_id_header(&id_packet.data)?;
+
+    let tags_packet = reader
+        .read_packet()
+        .map_err(|x| anyhow!("{}", x))?
+        .context("missing OpusTags packet")?;
+    let (loop_start, loop_end) = parse_loop_points(&tags_packet.data)?;
+    header.loop_start = loop_start;
+    header.loop_end = loop_end;
+
+    header.data_offset = header_size(&header);
+
+    let mut packets = vec![];
+    while let Some(packet) = reader.read_packet().map_err(|x| anyhow!("{}", x))? {
+        if header.channel_count > 2 {
+            packets.extend(split_multistream_packet(
+                &packet.data,
+                header.stream_count as usize,
+            )?);
+        } else {
+            packets.push(packet.data);
+        }
+    }
+
+    write_header(&mut out_file, &header)?;
+
+    // length prefix (4) + trailing field (4) + packet bytes, for every packet
+    let data_length: u32 = packets.iter().map(|p| p.len() as u32 + 8).sum();
+    write_data_header(&mut out_file, data_length)?;
+
+    for packet in &packets {
+        write_packet(&mut out_file, packet)?;
+    }
 
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let mut args = args_os().skip(1);
+    let mode = args.next().context("Missing mode!")?;
+    let in_path = args.next().context("Missing path!")?;
+    let out_path = args.next().context("Missing path!")?;
+
+    match mode.to_str() {
+        Some("ogg") => to_ogg(&in_path, &out_path),
+        Some("nxopus") => to_nxopus(&in_path, &out_path),
+        Some("wav") => to_wav(&in_path, &out_path),
+        Some("rtp") => {
+            let payload_type = args
+                .next()
+                .map(|x| -> Result<u8> {
+                    let x = x.to_str().context("payload type must be UTF-8")?;
+                    x.parse().context("payload type must be a number")
+                })
+                .transpose()?
+                .unwrap_or(111);
+            if !(96..=127).contains(&payload_type) {
+                return Err(anyhow!("payload type must be in 96..=127"));
+            }
+            to_rtp(&in_path, &out_path, payload_type)
+        }
+        _ => Err(anyhow!(
+            "unknown mode {:?}, expected `ogg`, `nxopus`, `wav`, or `rtp`",
+            mode
+        )),
+    }
+}